@@ -0,0 +1,160 @@
+//! Greedy meshing: turns a voxel grid into a small set of merged quads.
+//!
+//! Instead of emitting one quad per visible voxel face, `greedy_mesh`
+//! sweeps each of the six `Face` directions slice by slice and merges
+//! runs of equal-material, equal-visibility faces into maximal
+//! rectangles. This is the standard way to collapse flat surfaces in a
+//! chunked voxel renderer down from thousands of quads to a handful.
+
+use super::{ Face, FaceIterator, Vector3 };
+
+/// A single merged face, ready to feed into a vertex buffer.
+pub struct Quad {
+    /// The direction this quad faces.
+    pub face: Face,
+    /// The four corners of the quad, in the same order as `Face::vertices`.
+    pub vertices: [Vector3<f32>; 4],
+    /// The texture coordinate rectangle to use for this quad.
+    pub texcoord: [f32; 4]
+}
+
+// The two axes spanning a face's plane, in ascending order, given the
+// axis the face points along.
+fn plane_axes(axis: usize) -> (usize, usize) {
+    match axis {
+        0 => (1, 2),
+        1 => (0, 2),
+        _ => (0, 1)
+    }
+}
+
+/// Meshes a `dims[0] x dims[1] x dims[2]` voxel grid into merged quads.
+///
+/// `voxel(pos)` returns the material at `pos`, or `None` for empty space.
+/// A face is visible when the neighboring voxel in `face.direction()` is
+/// empty or holds a different material. `texcoord(material, face)`
+/// supplies the UV rectangle for a merged quad of that material and face.
+pub fn greedy_mesh<T, F, C>(dims: [usize; 3], voxel: F, texcoord: C) -> Vec<Quad>
+    where T: Eq + Copy,
+          F: Fn([i32; 3]) -> Option<T>,
+          C: Fn(T, Face) -> [f32; 4]
+{
+    let mut quads = vec![];
+
+    for face in FaceIterator::new() {
+        let direction = face.direction();
+        let axis = direction.iter().position(|&d| d != 0).unwrap();
+        let (u_axis, v_axis) = plane_axes(axis);
+        let (du, dv) = (dims[u_axis], dims[v_axis]);
+
+        for slice in 0 .. dims[axis] {
+            let mut mask: Vec<Option<T>> = Vec::with_capacity(du * dv);
+            for v in 0 .. dv {
+                for u in 0 .. du {
+                    let mut pos = [0i32; 3];
+                    pos[axis] = slice as i32;
+                    pos[u_axis] = u as i32;
+                    pos[v_axis] = v as i32;
+
+                    let visible = match voxel(pos) {
+                        None => None,
+                        Some(material) => {
+                            let neighbor = [
+                                pos[0] + direction[0],
+                                pos[1] + direction[1],
+                                pos[2] + direction[2]
+                            ];
+                            match voxel(neighbor) {
+                                Some(other) if other == material => None,
+                                _ => Some(material)
+                            }
+                        }
+                    };
+                    mask.push(visible);
+                }
+            }
+
+            for v in 0 .. dv {
+                let mut u = 0;
+                while u < du {
+                    let material = match mask[u + v * du] {
+                        None => { u += 1; continue }
+                        Some(material) => material
+                    };
+
+                    // Grow the run to the right as far as it matches.
+                    let mut width = 1;
+                    while u + width < du && mask[u + width + v * du] == Some(material) {
+                        width += 1;
+                    }
+
+                    // Grow the run downward as long as every cell in the
+                    // new row still matches the whole width.
+                    let mut height = 1;
+                    'grow_height: while v + height < dv {
+                        for w in 0 .. width {
+                            if mask[u + w + (v + height) * du] != Some(material) {
+                                break 'grow_height
+                            }
+                        }
+                        height += 1;
+                    }
+
+                    // Clear the merged rectangle so it isn't reused.
+                    for h in 0 .. height {
+                        for w in 0 .. width {
+                            mask[u + w + (v + h) * du] = None;
+                        }
+                    }
+
+                    let mut base = [0.0f32; 3];
+                    base[axis] = slice as f32;
+                    base[u_axis] = u as f32;
+                    base[v_axis] = v as f32;
+
+                    let mut scale = [0.0f32; 3];
+                    scale[axis] = 1.0;
+                    scale[u_axis] = width as f32;
+                    scale[v_axis] = height as f32;
+
+                    quads.push(Quad {
+                        face: face,
+                        vertices: face.vertices(base, scale),
+                        texcoord: texcoord(material, face)
+                    });
+
+                    u += width;
+                }
+            }
+        }
+    }
+
+    quads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ greedy_mesh, Face };
+
+    #[test]
+    fn flat_slab_merges_into_one_quad_per_face() {
+        // A 2x1x2 slab of a single material: every visible face should
+        // collapse into exactly one merged quad, not one per voxel.
+        let dims = [2, 1, 2];
+        let voxel = |pos: [i32; 3]| {
+            if pos[0] >= 0 && pos[0] < 2 && pos[1] == 0 && pos[2] >= 0 && pos[2] < 2 {
+                Some(1u8)
+            } else {
+                None
+            }
+        };
+        let texcoord = |_material: u8, _face: Face| [0.0, 0.0, 1.0, 1.0];
+
+        let quads = greedy_mesh(dims, voxel, texcoord);
+
+        let up = quads.iter().filter(|q| q.face == Face::Up).count();
+        let down = quads.iter().filter(|q| q.face == Face::Down).count();
+        assert_eq!(up, 1, "the top face should merge into a single quad");
+        assert_eq!(down, 1, "the bottom face should merge into a single quad");
+    }
+}
@@ -25,6 +25,9 @@
 
 use std::str::FromStr;
 
+/// Greedy meshing of a voxel grid into merged quads.
+pub mod mesh;
+
 /// A 3D vector.
 pub type Vector3<T> = [T; 3];
 
@@ -1,7 +1,6 @@
 //! Create textures and build texture atlas.
 
-use std::collections::HashMap;
-use std::collections::hash_map::Entry::{ Occupied, Vacant };
+use std::collections::{ hash_map, HashMap, VecDeque };
 use std::fmt::{ Debug, Formatter, Error };
 use std::path::{ Path, PathBuf };
 use std::mem;
@@ -10,6 +9,7 @@ use wgpu::{ Texture, TextureFormat, TextureDescriptor, TextureDimension, Device
 use image::{
     self,
     ImageBuffer,
+    Rgba,
     RgbaImage,
     DynamicImage,
     ImageResult,
@@ -86,22 +86,135 @@ impl ColorMap {
     }
 }
 
+/// An opaque handle to a tile's slot in the atlas, assigned the moment
+/// it is loaded. Use [`AtlasBuilder::tile_id`] to obtain one, then
+/// [`AtlasBuilder::touch_id`]/[`AtlasBuilder::evict_id`] to pin or evict
+/// a tile without holding on to its name.
+///
+/// [`AtlasBuilder::tile_id`]: struct.AtlasBuilder.html#method.tile_id
+/// [`AtlasBuilder::touch_id`]: struct.AtlasBuilder.html#method.touch_id
+/// [`AtlasBuilder::evict_id`]: struct.AtlasBuilder.html#method.evict_id
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct AllocId(u64);
+
+/// A cap on how much of the atlas stays resident, passed to
+/// [`AtlasBuilder::set_capacity`]. Once exceeded, `load` evicts
+/// least-recently-used tiles to make room instead of growing further.
+///
+/// [`AtlasBuilder::set_capacity`]: struct.AtlasBuilder.html#method.set_capacity
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CapacityLimit {
+    /// Caps the number of resident tiles.
+    Tiles(usize),
+    /// Caps total resident tile pixel data, in bytes (4 bytes per
+    /// pixel; gutters aren't counted since they don't hold tile data).
+    Bytes(u64)
+}
+
+/// The transparency semantics of a tile, so a renderer can sort and
+/// batch draws instead of scanning pixels itself.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TileKind {
+    /// Every pixel has alpha 255: draw with depth-write, no blending.
+    Opaque,
+    /// Alpha is only ever 0 or 255: draw with an alpha test.
+    Cutout,
+    /// Alpha takes on other values: draw back-to-front, blended.
+    Translucent
+}
+
+/// Iterates over the names of loaded tiles that share a [`TileKind`].
+/// Created by [`AtlasBuilder::tiles_by_kind`].
+///
+/// [`TileKind`]: enum.TileKind.html
+/// [`AtlasBuilder::tiles_by_kind`]: struct.AtlasBuilder.html#method.tiles_by_kind
+pub struct TilesByKind<'a> {
+    inner: hash_map::Iter<'a, String, TileSlot>,
+    kind: TileKind
+}
+
+impl<'a> Iterator for TilesByKind<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            match self.inner.next() {
+                Some((name, slot)) => if slot.kind == self.kind {
+                    return Some(name.as_str())
+                },
+                None => return None
+            }
+        }
+    }
+}
+
+/// Records where a tile ended up after [`AtlasBuilder::compact`].
+///
+/// [`AtlasBuilder::compact`]: struct.AtlasBuilder.html#method.compact
+pub struct TileMove {
+    /// The name of the moved tile.
+    pub name: String,
+    /// The tile's previous top-left position.
+    pub old_xy: (u32, u32),
+    /// The tile's new top-left position.
+    pub new_xy: (u32, u32),
+    /// The tile's size, unaffected by the move.
+    pub size: (u32, u32)
+}
+
+// Bookkeeping kept per resident tile.
+struct TileSlot {
+    pos: (u32, u32),
+    size: (u32, u32),
+    // The rectangle actually reserved in the allocator, including the
+    // gutter. This is what gets freed on eviction or compaction.
+    rect: [u32; 4],
+    alloc_id: AllocId,
+    kind: TileKind
+}
+
 /// Builds an atlas of textures.
+///
+/// Tiles are packed with a guillotine rectangle allocator, so tiles of
+/// different sizes can share the same atlas without wasting space. When
+/// a `capacity` is set, the least-recently-used tiles are evicted to make
+/// room for new ones instead of growing the atlas, and a defragmenting
+/// repack is attempted before giving up, which keeps VRAM bounded for
+/// streaming resource packs. A tile that still doesn't fit after that is
+/// a programmer error (too low a capacity, or tiles too large) and
+/// panics rather than silently exceeding the configured capacity.
 pub struct AtlasBuilder {
     image: RgbaImage,
     // Base path for loading tiles.
     path: PathBuf,
-    // Size of an individual tile.
+    // Size of an individual tile, used only to size the initial atlas
+    // and to trim extra animation frames stacked under a tile.
     unit_width: u32,
     unit_height: u32,
-    // Size of the entirely occupied square, in tiles.
-    completed_tiles_size: u32,
-    // Position in the current strip.
-    position: u32,
+    // Free rectangles available for packing, as `[x, y, w, h]`.
+    free_rects: Vec<[u32; 4]>,
     // Position cache for loaded tiles (in pixels).
-    tile_positions: HashMap<String, (u32, u32)>,
+    tile_positions: HashMap<String, TileSlot>,
     // Lowest-alpha cache for rectangles in the atlas.
-    min_alpha_cache: HashMap<(u32, u32, u32, u32), u8>
+    min_alpha_cache: HashMap<(u32, u32, u32, u32), u8>,
+    // Limit on resident tiles. `None` means unbounded, in which case the
+    // atlas grows instead of evicting.
+    capacity: Option<CapacityLimit>,
+    // Tile names ordered from most- to least-recently-used.
+    lru: VecDeque<String>,
+    // Reverse lookup from a tile's `AllocId` back to its name.
+    ids: HashMap<AllocId, String>,
+    next_alloc_id: u64,
+    // Moves accumulated by automatic defragmentation inside `allocate`,
+    // waiting to be collected through `take_pending_moves`.
+    pending_moves: Vec<TileMove>,
+    // Running total of resident tile pixel data, in bytes (4 bytes per
+    // pixel; gutters excluded), kept incrementally so `CapacityLimit::Bytes`
+    // doesn't have to rescan every tile on every eviction.
+    resident_bytes: u64,
+    // Border reserved around every tile so mip levels never bleed into
+    // their neighbors. Must be set before the first `load`.
+    gutter: u32
 }
 
 impl AtlasBuilder {
@@ -109,24 +222,51 @@ impl AtlasBuilder {
     pub fn new<P>(path: P, unit_width: u32, unit_height: u32) -> Self
         where P: Into<PathBuf>
     {
+        let (w, h) = (unit_width * 4, unit_height * 4);
         AtlasBuilder {
-            image: ImageBuffer::new(unit_width * 4, unit_height * 4),
+            image: ImageBuffer::new(w, h),
             path: path.into(),
             unit_width: unit_width,
             unit_height: unit_height,
-            completed_tiles_size: 0,
-            position: 0,
+            free_rects: vec![[0, 0, w, h]],
             tile_positions: HashMap::new(),
-            min_alpha_cache: HashMap::new()
+            min_alpha_cache: HashMap::new(),
+            capacity: None,
+            lru: VecDeque::new(),
+            ids: HashMap::new(),
+            next_alloc_id: 0,
+            pending_moves: Vec::new(),
+            resident_bytes: 0,
+            gutter: 0
         }
     }
 
+    /// Sets a cap, in tiles or bytes, on how many resident tiles `load`
+    /// will keep. Once exceeded, the least-recently-used tiles are
+    /// evicted to make room for new ones instead of growing the atlas.
+    /// Pass `None` to grow without bound, which is the default.
+    pub fn set_capacity(&mut self, capacity: Option<CapacityLimit>) {
+        self.capacity = capacity;
+    }
+
+    /// Reserves a `gutter`-pixel border around every tile loaded from now
+    /// on, so that downsampling a tile for a mip level never mixes in a
+    /// neighboring tile's pixels. Pass `2u32.pow(levels)` where `levels`
+    /// is the deepest mip level `complete_with_mipmaps` will build.
+    /// Must be called before the first `load`.
+    pub fn set_gutter(&mut self, gutter: u32) {
+        assert!(self.tile_positions.is_empty(),
+            "set_gutter must be called before any tile is loaded");
+        self.gutter = gutter;
+    }
+
     /// Loads a file into the texture atlas.
     /// Checks if the file is loaded and returns position within the atlas.
     /// The name should be specified without file extension.
     /// PNG is the only supported format.
     pub fn load(&mut self, name: &str) -> (u32, u32) {
-        if let Some(&pos) = self.tile_positions.get(name) {
+        if let Some(pos) = self.tile_positions.get(name).map(|slot| slot.pos) {
+            self.touch(name);
             return pos
         }
 
@@ -135,65 +275,265 @@ impl AtlasBuilder {
         let img = load_rgba8(&path).unwrap();
 
         let (iw, ih) = img.dimensions();
-        assert!(iw == self.unit_width);
-        assert!((ih % self.unit_height) == 0);
-        if ih > self.unit_height {
+        let (tw, th) = if ih > self.unit_height && (ih % self.unit_height) == 0 {
             println!("ignoring {} extra frames in '{}'", (ih / self.unit_height) - 1, name);
+            (iw, self.unit_height)
+        } else {
+            (iw, ih)
+        };
+
+        if let Some(limit) = self.capacity {
+            let incoming_bytes = tw as u64 * th as u64 * 4;
+            loop {
+                let over = match limit {
+                    CapacityLimit::Tiles(cap) => self.tile_positions.len() >= cap,
+                    CapacityLimit::Bytes(cap) => self.resident_bytes + incoming_bytes > cap
+                };
+                if !over || !self.evict_lru() { break }
+            }
         }
 
-        let (uw, uh) = (self.unit_width, self.unit_height);
-        let (w, h) = self.image.dimensions();
-        let size = self.completed_tiles_size;
-
-        // Expand the image buffer if necessary.
-        if self.position == 0 && (uw * size >= w || uh * size >= h) {
-            let old = mem::replace(&mut self.image, ImageBuffer::new(w * 2, h * 2));
-            for ix in 0 .. w {
-                for iy in 0 .. h {
-                    *self.image.get_pixel_mut(ix, iy) = old[(ix, iy)];
-                }
+        let (x, y) = self.allocate(tw, th);
+
+        let mut all_opaque = true;
+        let mut all_0_or_255 = true;
+        for ix in 0 .. tw {
+            for iy in 0 .. th {
+                let px = img[(ix, iy)];
+                *self.image.get_pixel_mut(ix + x, iy + y) = px;
+                let a = px[3];
+                if a != 255 { all_opaque = false; }
+                if a != 0 && a != 255 { all_0_or_255 = false; }
             }
+        }
+        let kind = if all_opaque { TileKind::Opaque }
+            else if all_0_or_255 { TileKind::Cutout }
+            else { TileKind::Translucent };
+        self.extrude_gutter(x, y, tw, th);
+
+        let pad = self.gutter;
+        let rect = [x - pad, y - pad, tw + 2 * pad, th + 2 * pad];
+        let alloc_id = AllocId(self.next_alloc_id);
+        self.next_alloc_id += 1;
+        self.tile_positions.insert(name.to_string(), TileSlot {
+            pos: (x, y),
+            size: (tw, th),
+            rect: rect,
+            alloc_id: alloc_id,
+            kind: kind
+        });
+        self.ids.insert(alloc_id, name.to_string());
+        self.lru.push_front(name.to_string());
+        self.resident_bytes += tw as u64 * th as u64 * 4;
+
+        (x, y)
+    }
 
-            /*
-            let mut dest = SubImage::new(&mut self.image, 0, 0, w, h);
-            for ((_, _, a), b) in dest.pixels_mut().zip(old.pixels()) {
-                *a = *b;
+    // Replicates a tile's border pixels outward into its reserved gutter,
+    // so box-filtering a mip level never blends in a neighboring tile.
+    fn extrude_gutter(&mut self, x: u32, y: u32, w: u32, h: u32) {
+        let pad = self.gutter;
+        if pad == 0 { return }
+
+        // Extend the left and right edges first...
+        for dy in 0 .. h {
+            let left = *self.image.get_pixel(x, y + dy);
+            let right = *self.image.get_pixel(x + w - 1, y + dy);
+            for p in 1 ..= pad {
+                *self.image.get_pixel_mut(x - p, y + dy) = left;
+                *self.image.get_pixel_mut(x + w - 1 + p, y + dy) = right;
             }
-            */
         }
 
-        let (x, y) = if self.position < size {
-            (self.position, size)
-        } else {
-            (size, self.position - size)
+        // ...then the top and bottom edges, spanning the full padded
+        // width so the corners pick up the already-extended side pixels.
+        for col in (x - pad) .. (x + w + pad) {
+            let top = *self.image.get_pixel(col, y);
+            let bottom = *self.image.get_pixel(col, y + h - 1);
+            for p in 1 ..= pad {
+                *self.image.get_pixel_mut(col, y - p) = top;
+                *self.image.get_pixel_mut(col, y + h - 1 + p) = bottom;
+            }
+        }
+    }
+
+    /// Returns the `AllocId` handle for a loaded tile, so it can later be
+    /// pinned or evicted via [`touch_id`]/[`evict_id`] without holding on
+    /// to the tile's name.
+    ///
+    /// [`touch_id`]: #method.touch_id
+    /// [`evict_id`]: #method.evict_id
+    pub fn tile_id(&self, name: &str) -> Option<AllocId> {
+        self.tile_positions.get(name).map(|slot| slot.alloc_id)
+    }
+
+    /// Returns (and clears) the moves caused by any automatic
+    /// defragmentation that `load` has triggered since the last call to
+    /// this method. Only non-empty when `capacity` is set: a `load` that
+    /// can't find room after evicting may repack every resident tile to
+    /// fit, which silently invalidates their old positions. Callers that
+    /// set a `capacity` should drain this after every `load`, the same
+    /// way they would patch UVs or GPU vertex data from moves returned
+    /// directly by `compact`.
+    pub fn take_pending_moves(&mut self) -> Vec<TileMove> {
+        mem::replace(&mut self.pending_moves, Vec::new())
+    }
+
+    /// Marks a tile as most-recently-used, so it survives eviction longer.
+    /// Returns `false` if no tile with that name is loaded.
+    pub fn touch(&mut self, name: &str) -> bool {
+        if !self.tile_positions.contains_key(name) {
+            return false
+        }
+        if let Some(i) = self.lru.iter().position(|n| n == name) {
+            self.lru.remove(i);
+        }
+        self.lru.push_front(name.to_string());
+        true
+    }
+
+    /// Same as [`touch`], but addresses the tile by its `AllocId`.
+    ///
+    /// [`touch`]: #method.touch
+    pub fn touch_id(&mut self, id: AllocId) -> bool {
+        match self.name_for_id(id) {
+            Some(name) => self.touch(&name),
+            None => false
+        }
+    }
+
+    /// Evicts a tile by name, freeing its rectangle back into the
+    /// allocator's free list. Returns `false` if no tile with that name
+    /// is loaded.
+    pub fn evict(&mut self, name: &str) -> bool {
+        let slot = match self.tile_positions.remove(name) {
+            Some(slot) => slot,
+            None => return false
         };
+        if let Some(i) = self.lru.iter().position(|n| n == name) {
+            self.lru.remove(i);
+        }
+        self.ids.remove(&slot.alloc_id);
+        let (x, y) = slot.pos;
+        let (w, h) = slot.size;
+        self.resident_bytes -= w as u64 * h as u64 * 4;
+        self.free_rects.push(slot.rect);
+        self.min_alpha_cache.remove(&(x, y, w, h));
+        true
+    }
 
-        self.position += 1;
-        if self.position >= size * 2 + 1 {
-            self.position = 0;
-            self.completed_tiles_size += 1;
+    /// Same as [`evict`], but addresses the tile by its `AllocId`.
+    ///
+    /// [`evict`]: #method.evict
+    pub fn evict_id(&mut self, id: AllocId) -> bool {
+        match self.name_for_id(id) {
+            Some(name) => self.evict(&name),
+            None => false
         }
+    }
 
-        {
-            let (x, y, w, h) = (x * uw, y * uh, uw, uh);
-            for ix in 0 .. w {
-                for iy in 0 .. h {
-                    *self.image.get_pixel_mut(ix + x, iy + y) = img[(ix, iy)];
-                }
+    // Resolves an `AllocId` back to the tile name it was assigned to.
+    fn name_for_id(&self, id: AllocId) -> Option<String> {
+        self.ids.get(&id).cloned()
+    }
+
+    // Evicts the single least-recently-used tile. Returns `false` if
+    // there was nothing left to evict.
+    fn evict_lru(&mut self) -> bool {
+        match self.lru.back().cloned() {
+            Some(name) => self.evict(&name),
+            None => false
+        }
+    }
+
+    // Finds room for a `(w, h)` tile plus its gutter. While a capacity is
+    // set, tries an in-place defragmentation pass before giving up any
+    // tile, then evicts the least-recently-used tile and tries again;
+    // each iteration either places the tile or permanently shrinks the
+    // resident set, so this always terminates, ending in a `panic!` once
+    // nothing is left to evict. With no capacity set, it just grows the
+    // atlas. Returns the tile's content position, inside the gutter.
+    fn allocate(&mut self, w: u32, h: u32) -> (u32, u32) {
+        let pad = self.gutter;
+        let (pw, ph) = (w + 2 * pad, h + 2 * pad);
+        if let Some((x, y)) = self.try_allocate(pw, ph) {
+            return (x + pad, y + pad)
+        }
+
+        // Fragmentation, not a lack of total free space, is often why
+        // this didn't fit: try a defragmenting repack once, up front,
+        // before giving up any tile. Retrying it on every eviction below
+        // would turn an O(n) eviction pass into O(n^2 log n), since each
+        // repack re-sorts and re-packs every still-resident tile.
+        if self.capacity.is_some() && self.defragment() {
+            if let Some((x, y)) = self.try_allocate(pw, ph) {
+                return (x + pad, y + pad)
             }
         }
 
-        /*
-        let mut dest = SubImage::new(&mut self.image, x * uw, y * uh, uw, uh);
-        for ((_, _, a), b) in dest.pixels_mut().zip(img.pixels()) {
-            *a = *b;
+        loop {
+            if self.capacity.is_none() {
+                self.grow();
+            } else if !self.evict_lru() {
+                panic!("AtlasBuilder: capacity exhausted and atlas is too \
+                        small to fit a new {}x{} tile even after evicting \
+                        and defragmenting everything else; call `compact()` \
+                        to shrink first or raise the capacity", w, h);
+            }
+            if let Some((x, y)) = self.try_allocate(pw, ph) {
+                return (x + pad, y + pad)
+            }
         }
-        */
+    }
+
+    // Repacks every currently loaded tile, largest-first, into the atlas
+    // at its _current_ size, without growing it. Unlike `compact`, this
+    // gives up and leaves everything untouched if it doesn't fit, instead
+    // of doubling the atlas until it does. Returns whether it fit.
+    fn defragment(&mut self) -> bool {
+        let (w, h) = self.image.dimensions();
+        let pad = self.gutter;
+
+        let mut tiles: Vec<(String, TileSlot)> = self.tile_positions.drain().collect();
+        tiles.sort_by(|a, b| {
+            let area = |slot: &TileSlot| slot.size.0 as u64 * slot.size.1 as u64;
+            area(&b.1).cmp(&area(&a.1))
+        });
+
+        let (free_rects, placements) = match pack_tiles(&tiles, w, h, pad) {
+            Some(packed) => packed,
+            None => {
+                self.tile_positions = tiles.into_iter().collect();
+                return false
+            }
+        };
+
+        let moves = self.apply_repack(w, h, tiles, free_rects, placements);
+        self.pending_moves.extend(moves);
+        true
+    }
 
-        *match self.tile_positions.entry(name.to_string()) {
-            Occupied(entry) => entry.into_mut(),
-            Vacant(entry) => entry.insert((x * uw, y * uh))
+    // Best-short-side-fit: picks the free rectangle that leaves the least
+    // leftover space on its shorter axis, places the tile in its top-left
+    // corner, and guillotine-splits the remainder into (at most) two new
+    // free rectangles.
+    fn try_allocate(&mut self, w: u32, h: u32) -> Option<(u32, u32)> {
+        try_allocate_in(&mut self.free_rects, w, h)
+    }
+
+    // Doubles the backing image and adds the newly exposed area as free
+    // rectangles, without disturbing any tile already packed.
+    fn grow(&mut self) {
+        let (w, h) = self.image.dimensions();
+        let old = mem::replace(&mut self.image, ImageBuffer::new(w * 2, h * 2));
+        for ix in 0 .. w {
+            for iy in 0 .. h {
+                *self.image.get_pixel_mut(ix, iy) = old[(ix, iy)];
+            }
         }
+
+        self.free_rects.push([w, 0, w, h * 2]);
+        self.free_rects.push([0, h, w, h]);
     }
 
     /// Finds the minimum alpha value in a given sub texture of the image.
@@ -218,6 +558,109 @@ impl AtlasBuilder {
         self.image.dimensions()
     }
 
+    /// Returns the transparency classification of a loaded tile.
+    pub fn tile_kind(&self, name: &str) -> Option<TileKind> {
+        self.tile_positions.get(name).map(|slot| slot.kind)
+    }
+
+    /// Iterates over the names of every loaded tile with the given `kind`.
+    pub fn tiles_by_kind(&self, kind: TileKind) -> TilesByKind {
+        TilesByKind {
+            inner: self.tile_positions.iter(),
+            kind: kind
+        }
+    }
+
+    /// Repacks every live tile, largest-first, into a fresh atlas,
+    /// undoing the fragmentation left behind by streaming and eviction.
+    /// Shrinks the backing image to the smallest power-of-two size that
+    /// still fits everything. Returns the moves so callers can patch any
+    /// UVs or GPU vertex data that referenced the old positions.
+    pub fn compact(&mut self) -> Vec<TileMove> {
+        let mut tiles: Vec<(String, TileSlot)> = self.tile_positions.drain().collect();
+        tiles.sort_by(|a, b| {
+            let area = |slot: &TileSlot| slot.size.0 as u64 * slot.size.1 as u64;
+            area(&b.1).cmp(&area(&a.1))
+        });
+
+        let pad = self.gutter;
+        let mut w = next_pow2(self.unit_width.max(1));
+        let mut h = next_pow2(self.unit_height.max(1));
+
+        let (free_rects, placements) = loop {
+            match pack_tiles(&tiles, w, h, pad) {
+                Some(packed) => break packed,
+                None => { w *= 2; h *= 2; }
+            }
+        };
+
+        self.apply_repack(w, h, tiles, free_rects, placements)
+    }
+
+    // Blits every tile from its old position into a fresh `w x h` image
+    // at the given `placements` (in the same order as `tiles`), then
+    // replaces `self.image`/`free_rects`/`tile_positions`/
+    // `min_alpha_cache` and redraws gutters. Shared by `compact` (which
+    // may also shrink or grow the atlas) and `defragment` (which always
+    // repacks at the current size).
+    fn apply_repack(
+        &mut self,
+        w: u32,
+        h: u32,
+        tiles: Vec<(String, TileSlot)>,
+        free_rects: Vec<[u32; 4]>,
+        placements: Vec<(u32, u32)>
+    ) -> Vec<TileMove> {
+        let pad = self.gutter;
+        let mut new_image: RgbaImage = ImageBuffer::new(w, h);
+        let mut moves = Vec::with_capacity(tiles.len());
+        let mut new_positions = HashMap::with_capacity(tiles.len());
+        let mut new_min_alpha_cache = HashMap::new();
+
+        for ((name, mut slot), (nx, ny)) in tiles.into_iter().zip(placements.into_iter()) {
+            let (ox, oy) = slot.pos;
+            let (tw, th) = slot.size;
+            for ix in 0 .. tw {
+                for iy in 0 .. th {
+                    let px = *self.image.get_pixel(ox + ix, oy + iy);
+                    *new_image.get_pixel_mut(nx + ix, ny + iy) = px;
+                }
+            }
+
+            if let Some(&alpha) = self.min_alpha_cache.get(&(ox, oy, tw, th)) {
+                new_min_alpha_cache.insert((nx, ny, tw, th), alpha);
+            }
+
+            moves.push(TileMove {
+                name: name.clone(),
+                old_xy: (ox, oy),
+                new_xy: (nx, ny),
+                size: (tw, th)
+            });
+
+            slot.pos = (nx, ny);
+            slot.rect = [nx - pad, ny - pad, tw + 2 * pad, th + 2 * pad];
+            new_positions.insert(name, slot);
+        }
+
+        self.image = new_image;
+        self.free_rects = free_rects;
+        self.tile_positions = new_positions;
+        self.min_alpha_cache = new_min_alpha_cache;
+
+        // Each tile moved independently, so its gutter has to be redrawn
+        // against its new neighbors rather than carried over.
+        if pad > 0 {
+            for mv in &moves {
+                let (nx, ny) = mv.new_xy;
+                let (tw, th) = mv.size;
+                self.extrude_gutter(nx, ny, tw, th);
+            }
+        }
+
+        moves
+    }
+
     /// Returns the complete texture atlas as a texture.
     pub fn complete(self, device: &mut Device) -> Texture
     {
@@ -273,4 +716,282 @@ impl AtlasBuilder {
 
         texture
     }
+
+    /// Returns the complete texture atlas as a mipmapped texture.
+    ///
+    /// Builds each level with a 2x2 box filter, premultiplying alpha
+    /// before averaging (and un-premultiplying after) so translucent
+    /// edges don't darken. `levels` caps how many mip levels are
+    /// generated; pass `None` to build the full chain down to a single
+    /// pixel on the shorter side. For correct results without bleeding
+    /// between neighboring tiles, load tiles with [`set_gutter`] set to
+    /// at least `2.pow(levels)`.
+    ///
+    /// [`set_gutter`]: #method.set_gutter
+    pub fn complete_with_mipmaps(self, device: &mut Device, levels: Option<u32>) -> Texture {
+        let size = self.image.dimensions();
+        let max_levels = mip_level_count(size.0, size.1);
+        let levels = levels.map_or(max_levels, |l| l.min(max_levels)).max(1);
+
+        let mut mips = Vec::with_capacity(levels as usize);
+        mips.push(self.image);
+        for _ in 1 .. levels {
+            let down = downsample(&mips[mips.len() - 1]);
+            mips.push(down);
+        }
+
+        let texture_extent = wgpu::Extent3d {
+            width: size.0,
+            height: size.1,
+            depth: 1,
+        };
+        let texture = device.create_texture(&TextureDescriptor {
+            array_layer_count: 1,
+            mip_level_count: levels,
+            sample_count: 1,
+            size: texture_extent,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::TRANSFER_DST,
+        });
+
+        let mut init_encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { todo: 0 });
+
+        for (level, mip) in mips.iter().enumerate() {
+            let (mw, mh) = mip.dimensions();
+            let texels = mip.as_raw();
+            let temp_buf = device
+                .create_buffer_mapped(texels.len(), wgpu::BufferUsage::TRANSFER_SRC)
+                .fill_from_slice(texels);
+            init_encoder.copy_buffer_to_texture(
+                wgpu::BufferCopyView {
+                    buffer: &temp_buf,
+                    offset: 0,
+                    row_pitch: 4 * mw,
+                    image_height: mh,
+                },
+                wgpu::TextureCopyView {
+                    texture: &texture,
+                    array_layer: 0,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 0.0,
+                    },
+                },
+                wgpu::Extent3d { width: mw, height: mh, depth: 1 },
+            );
+        }
+
+        let init_command_buf = init_encoder.finish();
+        device.get_queue().submit(&[init_command_buf]);
+
+        texture
+    }
+}
+
+// Best-short-side-fit guillotine allocation against an arbitrary free
+// list, shared by `AtlasBuilder::try_allocate` and `AtlasBuilder::compact`.
+fn try_allocate_in(free_rects: &mut Vec<[u32; 4]>, w: u32, h: u32) -> Option<(u32, u32)> {
+    let mut best: Option<(usize, u32)> = None;
+    for (i, &[_, _, fw, fh]) in free_rects.iter().enumerate() {
+        if fw < w || fh < h { continue }
+        let leftover = (fw - w).min(fh - h);
+        if best.map_or(true, |(_, best_leftover)| leftover < best_leftover) {
+            best = Some((i, leftover));
+        }
+    }
+
+    let (i, _) = match best {
+        Some(found) => found,
+        None => return None
+    };
+    let [x, y, fw, fh] = free_rects.swap_remove(i);
+
+    // Guillotine-cut the leftover L-region along its shorter axis.
+    let (leftover_w, leftover_h) = (fw - w, fh - h);
+    let (right, bottom) = if leftover_w <= leftover_h {
+        ([x + w, y, fw - w, fh], [x, y + h, w, fh - h])
+    } else {
+        ([x + w, y, fw - w, h], [x, y + h, fw, fh - h])
+    };
+    if right[2] > 0 && right[3] > 0 {
+        free_rects.push(right);
+    }
+    if bottom[2] > 0 && bottom[3] > 0 {
+        free_rects.push(bottom);
+    }
+
+    Some((x, y))
+}
+
+// Attempts to pack every tile, largest-first, into a single `w x h`
+// canvas from scratch, padding each by `pad` on every side. Returns the
+// resulting free list and each tile's content position (inside its
+// gutter) if they all fit, in the same order as `tiles`. Shared by
+// `AtlasBuilder::compact` (which grows `w`/`h` until this succeeds) and
+// `AtlasBuilder::defragment` (which gives up instead of growing).
+fn pack_tiles(tiles: &[(String, TileSlot)], w: u32, h: u32, pad: u32)
+    -> Option<(Vec<[u32; 4]>, Vec<(u32, u32)>)>
+{
+    let mut free_rects = vec![[0, 0, w, h]];
+    let mut placements = Vec::with_capacity(tiles.len());
+    for &(_, ref slot) in tiles {
+        let (tw, th) = slot.size;
+        match try_allocate_in(&mut free_rects, tw + 2 * pad, th + 2 * pad) {
+            Some((rx, ry)) => placements.push((rx + pad, ry + pad)),
+            None => return None
+        }
+    }
+    Some((free_rects, placements))
+}
+
+// Rounds up to the next power of two (minimum 1).
+fn next_pow2(x: u32) -> u32 {
+    let mut p = 1;
+    while p < x { p *= 2; }
+    p
+}
+
+// The number of mip levels (including the base level) for an image whose
+// shorter side is `min(w, h)`, i.e. `floor(log2(min(w, h))) + 1`.
+fn mip_level_count(w: u32, h: u32) -> u32 {
+    let m = w.min(h).max(1);
+    32 - m.leading_zeros()
+}
+
+// Halves an image with a 2x2 box filter. Colors are premultiplied by
+// alpha before averaging, and un-premultiplied after, so translucent
+// pixels don't darken opaque neighbors (or vice versa).
+fn downsample(src: &RgbaImage) -> RgbaImage {
+    let (w, h) = src.dimensions();
+    let (dw, dh) = ((w / 2).max(1), (h / 2).max(1));
+    let mut dst: RgbaImage = ImageBuffer::new(dw, dh);
+
+    for y in 0 .. dh {
+        for x in 0 .. dw {
+            let mut rgb_sum = [0u32; 3];
+            let mut alpha_sum = 0u32;
+            for dy in 0 .. 2 {
+                for dx in 0 .. 2 {
+                    let sx = (x * 2 + dx).min(w - 1);
+                    let sy = (y * 2 + dy).min(h - 1);
+                    let p = *src.get_pixel(sx, sy);
+                    let a = p[3] as u32;
+                    rgb_sum[0] += p[0] as u32 * a;
+                    rgb_sum[1] += p[1] as u32 * a;
+                    rgb_sum[2] += p[2] as u32 * a;
+                    alpha_sum += a;
+                }
+            }
+
+            let alpha = (alpha_sum / 4) as u8;
+            let rgb = if alpha_sum == 0 {
+                [0, 0, 0]
+            } else {
+                [
+                    (rgb_sum[0] / alpha_sum) as u8,
+                    (rgb_sum[1] / alpha_sum) as u8,
+                    (rgb_sum[2] / alpha_sum) as u8
+                ]
+            };
+            dst.put_pixel(x, y, Rgba([rgb[0], rgb[1], rgb[2], alpha]));
+        }
+    }
+
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ try_allocate_in, AtlasBuilder, AllocId, CapacityLimit, TileKind, TileSlot };
+
+    #[test]
+    fn try_allocate_in_packs_without_overlap() {
+        let mut free_rects = vec![[0, 0, 64, 64]];
+        let mut placed = vec![];
+        for &(w, h) in &[(32, 32), (16, 16), (8, 32), (20, 20)] {
+            let (x, y) = try_allocate_in(&mut free_rects, w, h)
+                .expect("64x64 atlas should fit these tiles");
+            placed.push([x, y, w, h]);
+        }
+
+        for i in 0 .. placed.len() {
+            for j in i + 1 .. placed.len() {
+                let [ax, ay, aw, ah] = placed[i];
+                let [bx, by, bw, bh] = placed[j];
+                let overlap = ax < bx + bw && bx < ax + aw
+                    && ay < by + bh && by < ay + ah;
+                assert!(!overlap, "tiles {} and {} overlap: {:?} vs {:?}",
+                        i, j, placed[i], placed[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn try_allocate_in_fails_when_too_big() {
+        let mut free_rects = vec![[0, 0, 64, 64]];
+        assert_eq!(try_allocate_in(&mut free_rects, 65, 1), None);
+        assert_eq!(try_allocate_in(&mut free_rects, 1, 65), None);
+        // The failed attempts must not have consumed the free list.
+        assert_eq!(try_allocate_in(&mut free_rects, 64, 64), Some((0, 0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "capacity exhausted")]
+    fn allocate_panics_instead_of_looping_forever_when_nothing_fits() {
+        let mut atlas = AtlasBuilder::new("unused", 4, 4);
+        atlas.set_capacity(Some(CapacityLimit::Tiles(1)));
+        // Bigger than the empty 16x16 canvas: nothing resident to evict
+        // or defragment, so this must panic rather than spin forever
+        // retrying the same failing allocation.
+        atlas.allocate(1000, 1000);
+    }
+
+    #[test]
+    fn allocate_defragments_before_evicting_to_fit_a_fragmented_request() {
+        // A 64x64 canvas with a single 32x32 resident tile sitting away
+        // from any corner, and its surrounding free space split into
+        // thin strips the way repeated eviction would fragment it. No
+        // single free rectangle fits a 32x40 tile, even though the total
+        // free area (3072px) is more than enough.
+        let mut atlas = AtlasBuilder::new("unused", 16, 16);
+        atlas.free_rects = vec![
+            [0, 0, 64, 16],
+            [0, 16, 16, 32],
+            [48, 16, 16, 32],
+            [0, 48, 64, 16]
+        ];
+        atlas.tile_positions.insert("a".to_string(), TileSlot {
+            pos: (16, 16),
+            size: (32, 32),
+            rect: [16, 16, 32, 32],
+            alloc_id: AllocId(0),
+            kind: TileKind::Opaque
+        });
+        atlas.ids.insert(AllocId(0), "a".to_string());
+        atlas.next_alloc_id = 1;
+        atlas.lru.push_front("a".to_string());
+        atlas.set_capacity(Some(CapacityLimit::Tiles(10)));
+
+        // Repacking "a" into a corner leaves one contiguous 32x64 strip,
+        // which is what makes this fit without evicting "a" or growing
+        // the atlas.
+        let (x, y) = atlas.allocate(32, 40);
+        assert_eq!(atlas.get_size(), (64, 64), "should not have grown");
+        assert!(atlas.tile_id("a").is_some(), "should not have evicted \"a\"");
+
+        let a_pos = atlas.tile_positions["a"].pos;
+        let (ax, ay) = a_pos;
+        let (aw, ah) = atlas.tile_positions["a"].size;
+        let overlap = x < ax + aw && ax < x + 32 && y < ay + ah && ay < y + 40;
+        assert!(!overlap, "new tile {:?} overlaps repacked \"a\" at {:?}",
+                (x, y, 32, 40), a_pos);
+
+        let moves = atlas.take_pending_moves();
+        assert_eq!(moves.len(), 1, "defragmenting \"a\" should report its move");
+        assert_eq!(moves[0].name, "a");
+    }
 }